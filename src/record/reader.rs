@@ -0,0 +1,358 @@
+// Copyright 2019 Fullstop000 <fullstop1005@gmail.com>.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Copyright (c) 2011 The LevelDB Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use crate::record::{RecordType, BLOCK_SIZE, HEADER_SIZE};
+use crate::storage::File;
+use crate::util::coding::decode_fixed_32;
+use crate::util::compress::{decompress, Compression};
+use crate::util::crc32;
+use crate::util::crypto::{decrypt, derive_nonce, Key};
+use crate::util::status::{Result, Status, WickErr};
+
+/// Reader reads the length-prefixed, optionally compressed and encrypted
+/// records written by `Writer`, from any `Storage` file handle. Mirrors
+/// `Writer`'s block framing so the two can round-trip through a plain
+/// `Vec<u8>`-backed file as easily as a real one.
+pub struct Reader {
+    src: Box<dyn File>,
+    // Bytes of the current block not yet handed out to `read_record`.
+    block_buf: Vec<u8>,
+    block_pos: usize,
+    // The block number of the data currently in `block_buf`.
+    block_number: u64,
+    // How many blocks have been read so far, used to assign the next
+    // `block_number` (the first block read is number 0).
+    blocks_read: u64,
+    eof: bool,
+    log_number: u64,
+    encryption: Option<Key>,
+    // Must match the `Compression` the paired `Writer` used, since the
+    // codec isn't re-derivable from the record bytes alone.
+    compression: Compression,
+}
+
+impl Reader {
+    pub fn new(src: Box<dyn File>) -> Self {
+        Self::new_with_options(src, 0, Compression::None, None)
+    }
+
+    /// `log_number`, `compression` and `encryption` must match the values
+    /// the writer used so records decode (and, for `encryption`, decrypt)
+    /// correctly.
+    pub fn new_with_options(
+        src: Box<dyn File>,
+        log_number: u64,
+        compression: Compression,
+        encryption: Option<Key>,
+    ) -> Self {
+        Self {
+            src,
+            block_buf: vec![],
+            block_pos: 0,
+            block_number: 0,
+            blocks_read: 0,
+            eof: false,
+            log_number,
+            encryption,
+            compression,
+        }
+    }
+
+    /// Reads the next complete record, decrypting and decompressing it if
+    /// necessary. Returns `Ok(None)` once the file is exhausted on a record
+    /// boundary.
+    pub fn read_record(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut record: Vec<u8> = vec![];
+        let mut in_fragmented_record = false;
+        loop {
+            let (rt, _offset, fragment) = match self.read_physical_record()? {
+                Some(f) => f,
+                None => {
+                    if in_fragmented_record {
+                        return Err(WickErr::new(
+                            Status::Corruption,
+                            Some("log file ended in the middle of a record"),
+                        ));
+                    }
+                    return Ok(None);
+                }
+            };
+            match rt {
+                RecordType::Full => {
+                    record = fragment;
+                    break;
+                }
+                RecordType::First => {
+                    record = fragment;
+                    in_fragmented_record = true;
+                }
+                RecordType::Middle => {
+                    if !in_fragmented_record {
+                        return Err(WickErr::new(Status::Corruption, Some("missing start of fragmented record")));
+                    }
+                    record.extend_from_slice(&fragment);
+                }
+                RecordType::Last => {
+                    if !in_fragmented_record {
+                        return Err(WickErr::new(Status::Corruption, Some("missing start of fragmented record")));
+                    }
+                    record.extend_from_slice(&fragment);
+                    break;
+                }
+            }
+        }
+        let record = self.finish(record)?;
+        Ok(Some(record))
+    }
+
+    // Decompresses a fully reassembled record body (decryption already
+    // happened per-fragment in `read_physical_record`, since the GCM tag is
+    // only valid over one physical record's ciphertext).
+    fn finish(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        decompress(self.compression.type_tag() as u8, &data)
+    }
+
+    // Reads one physical, block-framed record and returns its type, its
+    // absolute file offset (for nonce derivation), and its payload.
+    fn read_physical_record(&mut self) -> Result<Option<(RecordType, u64, Vec<u8>)>> {
+        loop {
+            if self.block_buf.len() - self.block_pos < HEADER_SIZE {
+                if !self.fill_block()? {
+                    return Ok(None);
+                }
+                continue;
+            }
+            let header = &self.block_buf[self.block_pos..self.block_pos + HEADER_SIZE];
+            let expected_crc = crc32::unmask(decode_fixed_32(header));
+            let size = (header[4] as usize) | ((header[5] as usize) << 8);
+            let rt = header[6];
+            if self.block_pos + HEADER_SIZE + size > self.block_buf.len() {
+                return Err(WickErr::new(Status::Corruption, Some("record body overruns block")));
+            }
+            let absolute_offset =
+                self.block_number * BLOCK_SIZE as u64 + self.block_pos as u64;
+            let body_start = self.block_pos + HEADER_SIZE;
+            let mut body = self.block_buf[body_start..body_start + size].to_vec();
+            self.block_pos = body_start + size;
+
+            if rt == 0 {
+                // Zero-fill padding written when a block couldn't fit
+                // another header; skip it and keep scanning.
+                continue;
+            }
+            let record_type = match rt {
+                1 => RecordType::Full,
+                2 => RecordType::First,
+                3 => RecordType::Middle,
+                4 => RecordType::Last,
+                _ => return Err(WickErr::new(Status::Corruption, Some("unknown record type"))),
+            };
+
+            let actual_crc = crc32::mask(crc32::extend(crc32::value(&[rt]), &body));
+            if actual_crc != expected_crc {
+                return Err(WickErr::new(Status::Corruption, Some("record crc mismatch")));
+            }
+
+            if let Some(key) = &self.encryption {
+                let nonce = derive_nonce(self.log_number, absolute_offset);
+                body = decrypt(key, &nonce, &body)?;
+            }
+            return Ok(Some((record_type, absolute_offset, body)));
+        }
+    }
+
+    // Reads the next `BLOCK_SIZE` chunk (or whatever remains of the file)
+    // into `block_buf`. Returns `false` on a clean end-of-file.
+    fn fill_block(&mut self) -> Result<bool> {
+        if self.eof {
+            return Ok(false);
+        }
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        let mut read = 0;
+        while read < BLOCK_SIZE {
+            match self
+                .src
+                .read(&mut buf[read..])
+                .map_err(|e| WickErr::new_from_raw(Status::IOError, Some("failed to read log block"), Box::new(e)))?
+            {
+                0 => break,
+                n => read += n,
+            }
+        }
+        if read == 0 {
+            self.eof = true;
+            return Ok(false);
+        }
+        if read < BLOCK_SIZE {
+            self.eof = true;
+        }
+        buf.truncate(read);
+        self.block_buf = buf;
+        self.block_pos = 0;
+        self.block_number = self.blocks_read;
+        self.blocks_read += 1;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::writer::Writer;
+    use crate::util::crypto::Key;
+    use crate::util::slice::Slice;
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    // A `Storage::File` double backed by a `Vec<u8>` shared between the
+    // writer and reader halves of a test, so records can round-trip without
+    // touching disk.
+    struct MemFile {
+        buf: Rc<RefCell<Vec<u8>>>,
+        pos: usize,
+    }
+
+    impl MemFile {
+        fn new() -> Self {
+            MemFile { buf: Rc::new(RefCell::new(vec![])), pos: 0 }
+        }
+
+        fn reader_handle(&self) -> Self {
+            MemFile { buf: self.buf.clone(), pos: 0 }
+        }
+    }
+
+    impl io::Read for MemFile {
+        fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+            let buf = self.buf.borrow();
+            let available = buf.len().saturating_sub(self.pos);
+            let n = out.len().min(available);
+            out[..n].copy_from_slice(&buf[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
+        }
+    }
+
+    impl io::Write for MemFile {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            let mut buf = self.buf.borrow_mut();
+            if self.pos == buf.len() {
+                buf.extend_from_slice(data);
+            } else {
+                let end = self.pos + data.len();
+                if end > buf.len() {
+                    buf.resize(end, 0);
+                }
+                buf[self.pos..end].copy_from_slice(data);
+            }
+            self.pos += data.len();
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl io::Seek for MemFile {
+        fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+            let len = self.buf.borrow().len() as i64;
+            let new_pos = match pos {
+                io::SeekFrom::Start(n) => n as i64,
+                io::SeekFrom::End(n) => len + n,
+                io::SeekFrom::Current(n) => self.pos as i64 + n,
+            };
+            self.pos = new_pos as usize;
+            Ok(self.pos as u64)
+        }
+    }
+
+    impl crate::storage::File for MemFile {}
+
+    fn round_trip(compression: Compression, encryption: Option<Key>) {
+        let file = MemFile::new();
+        let mut writer =
+            Writer::new_with_options(Box::new(file.reader_handle()), 7, compression, encryption.clone()).unwrap();
+        let records: &[&[u8]] = &[b"short record", &[b'x'; 40_000], b"final record"];
+        for r in records {
+            writer.add_record(&Slice::from(*r)).unwrap();
+        }
+
+        let mut reader = Reader::new_with_options(Box::new(file.reader_handle()), 7, compression, encryption);
+        for expected in records {
+            let got = reader.read_record().unwrap().expect("record should be present");
+            assert_eq!(got.as_slice(), *expected);
+        }
+        assert!(reader.read_record().unwrap().is_none());
+    }
+
+    #[test]
+    fn round_trips_plain_records() {
+        round_trip(Compression::None, None);
+    }
+
+    #[test]
+    fn round_trips_encrypted_records() {
+        round_trip(Compression::None, Some(Key::new([9u8; 32])));
+    }
+
+    #[test]
+    fn round_trips_snappy_compressed_records() {
+        round_trip(Compression::Snappy, None);
+    }
+
+    #[test]
+    fn round_trips_zstd_compressed_records() {
+        round_trip(Compression::Zstd { level: 3 }, None);
+    }
+
+    #[test]
+    fn round_trips_zstd_and_encryption_together() {
+        round_trip(Compression::Zstd { level: 3 }, Some(Key::new([9u8; 32])));
+    }
+
+    #[test]
+    fn survives_tag_reserve_boundary_with_encryption() {
+        // Regression test for a `usize` underflow: an earlier version of
+        // `add_record` only switched to a new block when fewer than
+        // `HEADER_SIZE` bytes were left, ignoring the encryption tag. That
+        // left a dead zone of up to `TAG_SIZE` bytes where `space = BLOCK_SIZE
+        // - block_offset - HEADER_SIZE - tag_reserve` underflowed. Drive
+        // `block_offset` to land exactly in that dead zone after the first
+        // record, then confirm a second record still writes and reads back
+        // cleanly instead of panicking.
+        use crate::util::crypto::TAG_SIZE;
+
+        let leftover_target = HEADER_SIZE + TAG_SIZE / 2;
+        let first_len = BLOCK_SIZE - leftover_target - HEADER_SIZE - TAG_SIZE;
+        let first = vec![b'a'; first_len];
+        let second = b"second record after the dead zone".to_vec();
+
+        let key = Key::new([3u8; 32]);
+        let file = MemFile::new();
+        let mut writer =
+            Writer::new_with_options(Box::new(file.reader_handle()), 1, Compression::None, Some(key.clone()))
+                .unwrap();
+        writer.add_record(&Slice::from(first.as_slice())).unwrap();
+        writer.add_record(&Slice::from(second.as_slice())).unwrap();
+
+        let mut reader = Reader::new_with_options(Box::new(file.reader_handle()), 1, Compression::None, Some(key));
+        assert_eq!(reader.read_record().unwrap().unwrap(), first);
+        assert_eq!(reader.read_record().unwrap().unwrap(), second);
+    }
+}
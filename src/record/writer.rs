@@ -15,28 +15,68 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-use std::fs::File;
-use std::io::{Result, Seek, SeekFrom, Write};
+use std::io::SeekFrom;
+use std::mem;
+use crate::storage::File;
 use crate::util::slice::Slice;
 use crate::record::{RecordType, BLOCK_SIZE, HEADER_SIZE};
 use crate::util::crc32;
-use std::mem;
 use crate::util::coding::encode_fixed_32;
+use crate::util::compress::{compress, Compression};
+use crate::util::crypto::{derive_nonce, encrypt, Key, TAG_SIZE};
+use crate::util::status::{Result, Status, WickErr};
 
-/// Writer writes records to an underlying log `File`.
+/// Writer writes records to an underlying log file, obtained through the
+/// `Storage` abstraction rather than a concrete `std::fs::File`. This lets
+/// the same record format run against real files, an in-memory test buffer,
+/// or any other `Storage` backend.
 pub struct Writer {
-    dest: File,
+    dest: Box<dyn File>,
     //Current offset in block
     block_offset: usize,
+    // Index of the current block within the file: starts at the opening
+    // offset / `BLOCK_SIZE` and grows by one each time `block_offset` is
+    // reset to 0. Combined with `block_offset` this
+    // gives each record a unique absolute offset, used to derive encryption
+    // nonces.
+    block_number: u64,
     // crc32c values for all supported record types.  These are
     // pre-computed to reduce the overhead of computing the crc of the
     // record type stored in the header.
-    crc_cache: [u32; (RecordType::Last as usize + 1) as usize]
+    crc_cache: [u32; (RecordType::Last as usize + 1) as usize],
+    // How every record's payload is compressed before being framed. Applied
+    // uniformly for the lifetime of this `Writer`.
+    compression: Compression,
+    // The log file's number, mixed into every record's encryption nonce so
+    // files sharing a key never derive the same nonce. `None` means records
+    // are written in plaintext.
+    log_number: u64,
+    encryption: Option<Key>,
 }
 
 impl Writer {
-    pub fn new(mut dest: File) -> Result<Self> {
-        let offset = dest.seek(SeekFrom::Current(0))?;
+    pub fn new(dest: Box<dyn File>) -> Result<Self> {
+        Self::new_with_options(dest, 0, Compression::None, None)
+    }
+
+    /// Like `new`, but compresses every record payload with `compression`
+    /// before framing it, the same codec choice SSTable blocks use.
+    pub fn new_with_compression(dest: Box<dyn File>, compression: Compression) -> Result<Self> {
+        Self::new_with_options(dest, 0, compression, None)
+    }
+
+    /// Full constructor: `log_number` identifies this log file for nonce
+    /// derivation and `encryption`, when set, makes every record payload
+    /// AES-256-GCM encrypted (after compression) before it's framed.
+    pub fn new_with_options(
+        mut dest: Box<dyn File>,
+        log_number: u64,
+        compression: Compression,
+        encryption: Option<Key>,
+    ) -> Result<Self> {
+        let offset = dest
+            .seek(SeekFrom::Current(0))
+            .map_err(|e| WickErr::new_from_raw(Status::IOError, Some("failed to seek log file"), Box::new(e)))?;
         let n = RecordType::Last as usize;
         let mut cache = [0; RecordType::Last as usize + 1];
         for h in 0..n +1 {
@@ -46,15 +86,19 @@ impl Writer {
         let w = Writer {
             dest,
             block_offset: offset as usize % BLOCK_SIZE,
+            block_number: offset / BLOCK_SIZE as u64,
             crc_cache: cache,
+            compression,
+            log_number,
+            encryption,
         };
         Ok(w)
     }
 
     /// Appends a slice into the underlying log file
     pub fn add_record(&mut self, s: &Slice) -> Result<()> {
-        let data = s.to_slice();
-        let mut left = s.size();
+        let data = compress(s.to_slice(), self.compression)?;
+        let mut left = data.len();
         let mut begin = true; // indicate the record should be a
         while left > 0 {
             invarint!(
@@ -62,23 +106,30 @@ impl Writer {
                 "[record writer] the 'block_offset' {} overflows the max BLOCK_SIZE {}",
                 self.block_offset, BLOCK_SIZE,
             );
+            // Encryption appends a fixed-size tag to every record, so a block
+            // must have room for the tag as well as the header before we'll
+            // start a record in it; otherwise `space` below would underflow.
+            let tag_reserve = if self.encryption.is_some() { TAG_SIZE } else { 0 };
             let leftover = BLOCK_SIZE - self.block_offset;
 
             // switch to a new block if the left size is not enough
-            // for a record header
-            if leftover < HEADER_SIZE {
+            // for a record header (plus the encryption tag, if any)
+            if leftover < HEADER_SIZE + tag_reserve {
                 if leftover != 0 {
                     // fill the rest of the block with zero
-                    self.dest.write_all(&[0;6][..leftover])?;
+                    self.dest
+                        .write_all(&vec![0u8; leftover])
+                        .map_err(|e| WickErr::new_from_raw(Status::IOError, Some("failed to pad log block"), Box::new(e)))?;
                 }
                 self.block_offset = 0; // use a new block
+                self.block_number += 1;
             };
             invarint!(
-                BLOCK_SIZE >= self.block_offset + HEADER_SIZE,
+                BLOCK_SIZE >= self.block_offset + HEADER_SIZE + tag_reserve,
                 "[record writer] the left space of block {} is less than header size {}",
-                BLOCK_SIZE - self.block_offset, HEADER_SIZE,
+                BLOCK_SIZE - self.block_offset, HEADER_SIZE + tag_reserve,
             );
-            let space = BLOCK_SIZE - self.block_offset - HEADER_SIZE;
+            let space = BLOCK_SIZE - self.block_offset - HEADER_SIZE - tag_reserve;
             let to_write = if left < space {
                 left
             } else {
@@ -107,6 +158,19 @@ impl Writer {
 
     // create formatted bytes and write into the file
     fn write(&mut self, rt: RecordType, data: &[u8]) -> Result<()> {
+        // When encryption is enabled the nonce is derived from this record's
+        // absolute offset, so it must be computed before anything below
+        // advances `block_offset`/`block_number`.
+        let encrypted;
+        let data = match &self.encryption {
+            Some(key) => {
+                let offset = self.block_number * BLOCK_SIZE as u64 + self.block_offset as u64;
+                let nonce = derive_nonce(self.log_number, offset);
+                encrypted = encrypt(key, &nonce, data)?;
+                encrypted.as_slice()
+            }
+            None => data,
+        };
         let size = data.len();
         invarint!(
             size <= 0xffff,
@@ -124,17 +188,18 @@ impl Writer {
         buf[5] = (size >> 8) as u8;
         buf[6] = rt as u8; // record type
 
-        // encode crc
+        // encode crc (covers the ciphertext + GCM tag when encryption is on)
         let mut crc = crc32::extend(self.crc_cache[rt as usize], data);
         crc = crc32::mask(crc);
         encode_fixed_32(&mut buf, crc);
 
         // write the header and the data
-        self.dest.write_all(&buf)?;
-        self.dest.write_all(data)?;
-        self.dest.flush()?;
+        let io_err = |e: std::io::Error| WickErr::new_from_raw(Status::IOError, Some("failed to write log record"), Box::new(e));
+        self.dest.write_all(&buf).map_err(io_err)?;
+        self.dest.write_all(data).map_err(io_err)?;
+        self.dest.flush().map_err(io_err)?;
         // update block_offset
         self.block_offset += HEADER_SIZE + size;
         Ok(())
     }
-}
\ No newline at end of file
+}
@@ -16,11 +16,9 @@
 // found in the LICENSE file.
 
 use crate::storage::Storage;
-use std::rc::Rc;
 use crate::options::{Options, ReadOptions};
 use crate::cache::{Cache, HandleRef};
 use crate::sstable::table::{Table, new_table_iterator};
-use std::cell::RefCell;
 use crate::cache::lru::SharedLRUCache;
 use crate::util::status::Result;
 use crate::util::varint::VarintU64;
@@ -28,56 +26,126 @@ use crate::db::filename::{generate_filename, FileType};
 use crate::util::slice::Slice;
 use crate::iterator::{Iterator, EmptyIterator, IterWithCleanup, ConcatenateIterator};
 use crate::db::format::ParsedInternalKey;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use ahash::RandomState;
 
-/// A `TableCache` is the cache for the sst files and the sstable in them
+// Sharding spreads cached tables across independent LRU instances keyed by
+// file number, so concurrent `get`/`new_iter` calls touching different sst
+// files don't serialize on one lock. 16 shards is plenty for the file-number
+// keyspace without making per-shard capacity so coarse that a hot file can't
+// fit its neighbours.
+const NUM_SHARD_BITS: u32 = 4;
+const NUM_SHARDS: usize = 1 << NUM_SHARD_BITS;
+
+/// A `TableCache` is the cache for the sst files and the sstable in them.
+/// It's `Send + Sync` so it can live behind an `Arc` shared by compaction
+/// and read threads, and sharded (see `NUM_SHARDS`) so those threads don't
+/// serialize on one lock when they touch different sst files.
+///
+/// `Table::open` is handed the full `Options` on every cache miss below;
+/// decoding per-block codec tags is `Table`'s responsibility, not this
+/// cache's. SSTable blocks are not encrypted, so no decryption happens on
+/// this path.
 pub struct TableCache {
     env: Arc<dyn Storage>,
     db_name: String,
-    options: Rc<Options>,
-    // the key of cache is the file number
-    cache: Rc<RefCell<dyn Cache<Rc<Table>>>>,
+    options: Arc<Options>,
+    hash_builder: RandomState,
+    // the key of each shard's cache is the file number.
+    shards: Arc<Vec<Mutex<Shard>>>,
+}
+
+// One LRU shard plus the charge we recorded for each of its entries. We
+// track charges ourselves, keyed by file number, rather than asking the
+// cache for its total: `resident_bytes()` only reflects inserts/evictions
+// this `TableCache` made, so it won't notice an entry `SharedLRUCache` drops
+// on its own once a shard is over capacity.
+struct Shard {
+    cache: SharedLRUCache<Arc<Table>>,
+    charges: HashMap<u64, usize, RandomState>,
 }
 
 impl TableCache {
-    pub fn new(db_name: String, options: Rc<Options>, size: usize) -> Self {
-        let cache = Rc::new(RefCell::new(SharedLRUCache::<Rc<Table>>::new(size)));
+    /// `byte_budget` bounds the summed charges of cached tables, not a count
+    /// of entries. Each table is charged its on-disk file size; the memory
+    /// it actually pins (index, filter, cached blocks) is not measured. The
+    /// budget is divided evenly across the cache's shards.
+    pub fn new(db_name: String, options: Arc<Options>, byte_budget: usize) -> Self {
+        let per_shard = (byte_budget + NUM_SHARDS - 1) / NUM_SHARDS;
+        let hash_builder = RandomState::new();
+        let shards = (0..NUM_SHARDS)
+            .map(|_| {
+                Mutex::new(Shard {
+                    cache: SharedLRUCache::<Arc<Table>>::new(per_shard),
+                    charges: HashMap::with_hasher(hash_builder.clone()),
+                })
+            })
+            .collect();
         Self {
             env: options.env.clone(),
             db_name,
             options,
-            cache,
+            hash_builder,
+            shards: Arc::new(shards),
         }
     }
 
-    // Try to find the sst file from cache. If not found, try to find the file from storage and insert it into the cache
-    fn find_table(&self, file_number: u64, file_size: u64) -> Result<HandleRef<Rc<Table>>> {
+    /// Returns the total bytes this `TableCache` has charged for tables it
+    /// currently has inserted, across all shards. Callers can poll this to
+    /// monitor memory pressure against the configured budget.
+    pub fn resident_bytes(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|s| s.lock().unwrap().charges.values().sum::<usize>())
+            .sum()
+    }
+
+    fn key_and_shard(&self, file_number: u64) -> (Vec<u8>, usize) {
         let mut key = vec![];
         VarintU64::put_varint(&mut key, file_number);
-        match self.cache.borrow().look_up(key.as_slice()) {
+        let mut hasher = self.hash_builder.build_hasher();
+        hasher.write(&key);
+        let shard = (hasher.finish() as usize) & (NUM_SHARDS - 1);
+        (key, shard)
+    }
+
+    // Try to find the sst file from cache. If not found, try to find the file from storage and insert it into the cache
+    fn find_table(&self, file_number: u64, file_size: u64) -> Result<HandleRef<Arc<Table>>> {
+        let (key, shard_idx) = self.key_and_shard(file_number);
+        let shard = &self.shards[shard_idx];
+        match shard.lock().unwrap().cache.look_up(key.as_slice()) {
             Some(handle) => Ok(handle),
             None => {
                 let filename = generate_filename(self.db_name.as_str(), FileType::Table, file_number);
                 let table_file= self.env.open(filename.as_str())?;
                 let table = Table::open(table_file, file_size, self.options.clone())?;
-                return Ok(self.cache.borrow_mut().insert(key,  Rc::new(table), 1, None));
+                // Charge by on-disk file size, which is already in hand here;
+                // the table's in-memory footprint is not measured.
+                let charge = file_size as usize;
+                let mut shard = shard.lock().unwrap();
+                shard.charges.insert(file_number, charge);
+                return Ok(shard.cache.insert(key, Arc::new(table), charge, None));
             }
         }
     }
 
     /// Evict any entry for the specified file number
-    pub fn evict(&mut self, file_number: u64) {
-        let mut key = vec![];
-        VarintU64::put_varint(&mut key, file_number);
-        self.cache.borrow_mut().erase(key.as_slice());
+    pub fn evict(&self, file_number: u64) {
+        let (key, shard_idx) = self.key_and_shard(file_number);
+        let mut shard = self.shards[shard_idx].lock().unwrap();
+        shard.charges.remove(&file_number);
+        shard.cache.erase(key.as_slice());
     }
 
     /// Returns the result of a seek to internal key `key` in specified file
-    pub fn get(&self, options: Rc<ReadOptions>, key: &Slice, file_number: u64, file_size: u64) -> Result<Option<ParsedInternalKey>> {
+    pub fn get(&self, options: Arc<ReadOptions>, key: &Slice, file_number: u64, file_size: u64) -> Result<Option<ParsedInternalKey>> {
+        let (_, shard) = self.key_and_shard(file_number);
         let handle = self.find_table(file_number, file_size)?;
         // every value should be valid so unwrap is safe here
         let parsed_key = handle.borrow().get_value().unwrap().internal_get(options, key.as_slice())?;
-        self.cache.borrow_mut().release(handle);
+        self.shards[shard].lock().unwrap().cache.release(handle);
         Ok(parsed_key)
     }
 
@@ -88,16 +156,17 @@ impl TableCache {
     /// Entry format:
     ///     key: internal key
     ///     value: value of user key
-    pub fn new_iter(&self, options: Rc<ReadOptions>, file_number: u64, file_size: u64) -> Box<dyn Iterator> {
+    pub fn new_iter(&self, options: Arc<ReadOptions>, file_number: u64, file_size: u64) -> Box<dyn Iterator> {
+        let (_, shard) = self.key_and_shard(file_number);
         match self.find_table(file_number, file_size) {
             Ok(h) => {
                 let table = h.borrow().get_value().unwrap();
                 let mut iter = IterWithCleanup::new(new_table_iterator(table, options));
-                let cache = self.cache.clone();
-                iter.register_task(Box::new(move || cache.borrow_mut().release(h.clone())));
+                let shards = self.shards.clone();
+                iter.register_task(Box::new(move || shards[shard].lock().unwrap().cache.release(h.clone())));
                 Box::new(iter)
             }
             Err(e) => EmptyIterator::new_with_err(e)
         }
     }
-}
\ No newline at end of file
+}
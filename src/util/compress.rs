@@ -0,0 +1,136 @@
+// Copyright 2019 Fullstop000 <fullstop1005@gmail.com>.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::util::status::{Result, Status, WickErr};
+
+/// The one-byte tag that tells a reader how a payload is encoded, with the
+/// values of the SSTable block trailer byte. WAL records do not store it:
+/// `record::Reader` passes the tag of its configured `Compression`.
+/// `None`/`Snappy` keep the original LevelDB wire values so existing files
+/// stay readable; `Zstd` is new.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionType {
+    None = 0x0,
+    Snappy = 0x1,
+    Zstd = 0x2,
+}
+
+impl CompressionType {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0x0 => Some(CompressionType::None),
+            0x1 => Some(CompressionType::Snappy),
+            0x2 => Some(CompressionType::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// The codec a writer should use for new blocks/records. `Zstd` carries the
+/// usual zstd compression level (1-22, higher is slower and smaller).
+#[derive(Debug, Clone, Copy)]
+pub enum Compression {
+    None,
+    Snappy,
+    Zstd { level: i32 },
+}
+
+impl Compression {
+    pub fn type_tag(self) -> CompressionType {
+        match self {
+            Compression::None => CompressionType::None,
+            Compression::Snappy => CompressionType::Snappy,
+            Compression::Zstd { .. } => CompressionType::Zstd,
+        }
+    }
+}
+
+/// Every zstd frame starts with this 4-byte magic number.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+/// Compresses `data` per `compression`. `None` is a no-op copy so callers
+/// can always write the returned bytes unconditionally.
+pub fn compress(data: &[u8], compression: Compression) -> Result<Vec<u8>> {
+    match compression {
+        Compression::None => Ok(data.to_vec()),
+        Compression::Snappy => snap::raw::Encoder::new().compress_vec(data).map_err(|e| {
+            WickErr::new_from_raw(Status::CompressionError, Some("snappy compression failed"), Box::new(e))
+        }),
+        Compression::Zstd { level } => zstd::stream::encode_all(data, level).map_err(|e| {
+            WickErr::new_from_raw(Status::CompressionError, Some("zstd compression failed"), Box::new(e))
+        }),
+    }
+}
+
+/// Decompresses a block/record payload previously produced by [`compress`].
+/// `tag` is the block trailer byte, or for WAL records the tag of the
+/// reader's configured `Compression`. A zstd
+/// payload missing its frame magic, or an unrecognized tag altogether, is
+/// rejected with `Status::CompressionError` rather than silently passed
+/// through, since either case almost always means the file is corrupt.
+pub fn decompress(tag: u8, data: &[u8]) -> Result<Vec<u8>> {
+    match CompressionType::from_u8(tag) {
+        Some(CompressionType::None) => Ok(data.to_vec()),
+        Some(CompressionType::Snappy) => snap::raw::Decoder::new().decompress_vec(data).map_err(|e| {
+            WickErr::new_from_raw(Status::CompressionError, Some("snappy decompression failed"), Box::new(e))
+        }),
+        Some(CompressionType::Zstd) => {
+            if data.len() < ZSTD_MAGIC.len() || data[..ZSTD_MAGIC.len()] != ZSTD_MAGIC {
+                return Err(WickErr::new(Status::CompressionError, Some("missing zstd frame magic")));
+            }
+            zstd::stream::decode_all(data).map_err(|e| {
+                WickErr::new_from_raw(Status::CompressionError, Some("zstd decompression failed"), Box::new(e))
+            })
+        }
+        None => Err(WickErr::new(Status::CompressionError, Some("unknown compression type tag"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(compression: Compression) {
+        let data = b"wickdb wickdb wickdb wickdb wickdb wickdb wickdb wickdb".repeat(64);
+        let compressed = compress(&data, compression).unwrap();
+        let decompressed = decompress(compression.type_tag() as u8, &compressed).unwrap();
+        assert_eq!(decompressed, data);
+    }
+
+    #[test]
+    fn none_round_trip_is_a_copy() {
+        round_trip(Compression::None);
+    }
+
+    #[test]
+    fn snappy_round_trip() {
+        round_trip(Compression::Snappy);
+    }
+
+    #[test]
+    fn zstd_round_trip() {
+        round_trip(Compression::Zstd { level: 3 });
+    }
+
+    #[test]
+    fn zstd_decompress_rejects_missing_frame_magic() {
+        let err = decompress(CompressionType::Zstd as u8, b"not a zstd frame").unwrap_err();
+        assert_eq!(*err.status(), Status::CompressionError);
+    }
+
+    #[test]
+    fn decompress_rejects_unknown_tag() {
+        let err = decompress(0xff, b"").unwrap_err();
+        assert_eq!(*err.status(), Status::CompressionError);
+    }
+}
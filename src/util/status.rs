@@ -18,7 +18,7 @@
 use std::fmt::{Display, Formatter};
 use std::error::Error;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Status {
     NotFound,
     Corruption,
@@ -64,6 +64,12 @@ impl WickErr {
             raw: Some(raw),
         }
     }
+
+    /// The `Status` this error was raised with, e.g. for callers/tests that
+    /// need to assert on the error kind rather than its message.
+    pub fn status(&self) -> &Status {
+        &self.t
+    }
 }
 
 impl Display for WickErr {